@@ -2,10 +2,18 @@ use super::model::*;
 use super::xml::{BcPayloads, BcXml, BcXmls};
 use super::xml_crypto;
 use err_derive::Error;
-use log::*;
 use nom::IResult;
 use nom::{bytes::streaming::take, combinator::*, number::streaming::*, sequence::*};
+use std::convert::TryFrom;
 use std::io::Read;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use aes::Aes128;
+use cfb_mode::cipher::{AsyncStreamCipher, NewCipher};
+use cfb_mode::Cfb;
+
+/// AES-128 in CFB mode, the block cipher negotiated by newer firmware.
+type AesCfb = Cfb<Aes128>;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -17,6 +25,131 @@ pub enum Error {
 
 type NomErrorTuple<'a> = (&'a [u8], nom::error::ErrorKind);
 
+/// How hard the decoder insists on understanding every frame.
+///
+/// In `Tolerant` mode (the default carried by [`BcContext`]) a frame whose body
+/// cannot be interpreted — XML that will not parse, or a legacy `msg_id` we do
+/// not model — is preserved verbatim as [`BcBody::Raw`] so a proxy can
+/// re-forward it untouched. In `Strict` mode those same cases are hard errors,
+/// which is what we want when validating input from an untrusted peer rather
+/// than leniently decoding known-good stored data.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    Strict,
+    #[default]
+    Tolerant,
+}
+
+/// The known Baichuan message types, as a typed alternative to matching the raw
+/// `u32` `msg_id`. Values we do not model are kept as `Unknown` so dispatch
+/// stays exhaustive instead of scattering magic numbers through the decoder.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MsgId {
+    Login,
+    VideoStart,
+    Ptz,
+    Unknown(u32),
+}
+
+// The mapping is total — unrecognised ids become `Unknown` rather than failing —
+// but the protocol spells this conversion as `TryFrom<u32>` so the door stays
+// open for genuinely fallible variants later.
+#[allow(clippy::infallible_try_from)]
+impl TryFrom<u32> for MsgId {
+    type Error = std::convert::Infallible;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            MSG_ID_LOGIN => MsgId::Login,
+            MSG_ID_VIDEO => MsgId::VideoStart,
+            MSG_ID_PTZ => MsgId::Ptz,
+            other => MsgId::Unknown(other),
+        })
+    }
+}
+
+impl BcHeader {
+    /// The message type as a typed [`MsgId`], resolving the raw `msg_id` field.
+    pub fn msg_id(&self) -> MsgId {
+        // The conversion is infallible, so the `Err` arm can never be taken.
+        MsgId::try_from(self.msg_id).unwrap()
+    }
+}
+
+/// Internal result of interpreting a modern message body: either a fully
+/// understood `ModernMsg`, or a request that the caller fall back to a raw frame
+/// (honouring the active [`ParseMode`]).
+enum Understood<T> {
+    Yes(T),
+    Raw,
+}
+
+/// Initialisation vector for the AES-128-CFB session cipher. It is reset for
+/// every message, so a fixed value is all the protocol needs.
+const AES_IV: &[u8; 16] = b"0123456789abcdef";
+
+/// The cipher protecting a modern message's XML and payload blocks.
+///
+/// Older firmware only ever uses [`BcLegacyXor`](EncryptionProtocol::BcLegacyXor)
+/// — the XOR-with-`enc_offset` scheme in [`xml_crypto`]. Newer firmware
+/// negotiates a real block cipher during login: the key is derived from the
+/// `encryption.nonce` carried in the login XML together with the account
+/// password, and [`Aes`](EncryptionProtocol::Aes) is installed on the
+/// [`BcContext`] once the handshake completes so subsequent frames decrypt
+/// automatically.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum EncryptionProtocol {
+    Unencrypted,
+    #[default]
+    BcLegacyXor,
+    Aes { key: [u8; 16] },
+}
+
+impl EncryptionProtocol {
+    /// Derive the AES session key from a negotiated nonce and the account
+    /// password: the first sixteen bytes of the hex-encoded `MD5("{nonce}-{password}")`.
+    pub fn aes_from_nonce(nonce: &str, password: &str) -> EncryptionProtocol {
+        let digest = md5::compute(format!("{}-{}", nonce, password).as_bytes());
+        let hex = format!("{:x}", digest);
+        let mut key = [0u8; 16];
+        key.copy_from_slice(&hex.as_bytes()[..16]);
+        EncryptionProtocol::Aes { key }
+    }
+
+    /// Decrypt one message block. `offset` is the header's `enc_offset`, used by
+    /// the legacy XOR cipher and ignored by the others. AES resets its IV per
+    /// call, so every message is decrypted independently.
+    pub fn decrypt(&self, offset: u32, buf: &[u8]) -> Vec<u8> {
+        match self {
+            EncryptionProtocol::Unencrypted => buf.to_vec(),
+            EncryptionProtocol::BcLegacyXor => xml_crypto::crypt(offset, buf),
+            EncryptionProtocol::Aes { key } => {
+                let mut data = buf.to_vec();
+                AesCfb::new_from_slices(key, AES_IV)
+                    .expect("AES-128-CFB key and IV are fixed-length and valid")
+                    .decrypt(&mut data);
+                data
+            }
+        }
+    }
+}
+
+/// The ciphers to try for an incoming message, newest first. Unencrypted frames
+/// need no cipher; encrypted ones are offered the active protocol followed by
+/// the previous one (if any) so a frame that arrives after a re-key but was
+/// encrypted under the old key still decodes.
+fn decrypt_candidates(context: &BcContext, encrypted: bool) -> Vec<EncryptionProtocol> {
+    if !encrypted {
+        return vec![EncryptionProtocol::Unencrypted];
+    }
+
+    let mut candidates = vec![context.encryption_protocol.clone()];
+    if let Some(prior) = &context.prior_encryption_protocol {
+        candidates.push(prior.clone());
+    }
+    candidates
+}
+
 impl<'a> From<nom::Err<NomErrorTuple<'a>>> for Error {
     fn from(k: nom::Err<NomErrorTuple<'a>>) -> Self {
         let reason = match k {
@@ -33,6 +166,77 @@ impl Bc {
         // Throw away the nom-specific return types
         read_from_reader(|reader| bc_msg(context, reader), r)
     }
+
+    /// Take a specific parsed payload out of a modern message, removing it from
+    /// the `Bc` and leaving any non-matching payload in place.
+    ///
+    /// Returns `Ok(None)` when the payload is absent (including on legacy or raw
+    /// messages) and passes on any error from the [`TakePayload`] impl, so
+    /// handling code reads `if let Some(enc) = bc.take_payload::<Encryption>()? { ... }`
+    /// instead of a nested `match` over `ModernMsg { xml, payload }`. None of the
+    /// impls in this crate returns an error: payloads are parsed when the message
+    /// is decoded, and a payload block that did not parse as XML is stored as
+    /// binary, so taking it as [`BcXml`] yields `Ok(None)`.
+    pub fn take_payload<T: TakePayload>(&mut self) -> Result<Option<T>, Error> {
+        match &mut self.body {
+            BcBody::ModernMsg(msg) => T::take_payload(msg),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// A payload that can be lifted out of a [`ModernMsg`] by type.
+///
+/// Implemented by the concrete payload kinds a modern message can carry. The
+/// XML element types parsed into the top-level [`BcXmls`] document (such as
+/// [`Encryption`](super::xml::Encryption)) provide their own impls alongside
+/// their definitions; the cases for a whole payload XML document and for a raw
+/// binary block live here.
+pub trait TakePayload: Sized {
+    /// Remove this payload from `msg`. Yields `Ok(None)` if the message does not
+    /// carry this kind of payload (leaving whatever it does carry untouched) and
+    /// an error if the payload is present but cannot be understood as `Self`.
+    fn take_payload(msg: &mut ModernMsg) -> Result<Option<Self>, Error>;
+}
+
+impl TakePayload for BcXml {
+    fn take_payload(msg: &mut ModernMsg) -> Result<Option<Self>, Error> {
+        match msg.payload {
+            Some(BcPayloads::BcXml(_)) => match msg.payload.take() {
+                Some(BcPayloads::BcXml(xml)) => Ok(Some(xml)),
+                _ => unreachable!("payload matched BcXml immediately above"),
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+impl TakePayload for Vec<u8> {
+    fn take_payload(msg: &mut ModernMsg) -> Result<Option<Self>, Error> {
+        match msg.payload {
+            Some(BcPayloads::Binary(_)) => match msg.payload.take() {
+                Some(BcPayloads::Binary(bytes)) => Ok(Some(bytes)),
+                _ => unreachable!("payload matched Binary immediately above"),
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+impl ModernMsg {
+    /// The parsed top-level XML of this message, if it carried any.
+    pub fn xml(&self) -> Option<&BcXmls> {
+        self.xml.as_ref()
+    }
+
+    /// The raw binary payload of this message, if its payload block was binary
+    /// rather than XML.
+    pub fn binary(&self) -> Option<&[u8]> {
+        match &self.payload {
+            Some(BcPayloads::Binary(bytes)) => Some(bytes),
+            _ => None,
+        }
+    }
 }
 
 fn read_from_reader<P, O, E, R>(mut parser: P, mut rdr: R) -> Result<O, E>
@@ -64,58 +268,273 @@ where
     }
 }
 
-fn bc_msg<'a, 'b>(context: &'a mut BcContext, buf: &'b [u8]) -> IResult<&'b [u8], Bc> {
-    let (buf, header) = bc_header(buf)?;
-    let (buf, body) = bc_body(context, &header, buf)?;
+/// Number of bytes to pull from the socket when the parser cannot tell us how
+/// much more it needs (`nom::Needed::Unknown`).
+const CHUNK_SIZE: usize = 4096;
+
+/// A stateful decoder that yields `Bc` messages one after another off a single
+/// connection.
+///
+/// Unlike [`Bc::deserialize`], which allocates a fresh accumulator and throws
+/// away whatever trailed the message it parsed, a `BcDecoder` owns one growing
+/// buffer for the life of the stream: bytes left over after a complete `bc_msg`
+/// parse are retained and fed straight into the next message, and the socket is
+/// only touched again when the parser reports `nom::Err::Incomplete`.
+///
+/// It exposes a blocking [`Iterator`] for `std::io::Read` sources (via
+/// [`iter_messages`](BcDecoder::iter_messages)) and an async
+/// [`Stream`](futures::Stream) for `tokio::io::AsyncRead` sources (via
+/// [`stream`](BcDecoder::stream)). A clean end-of-stream on a message boundary
+/// ends iteration with `None`; an EOF in the middle of a frame surfaces as an
+/// `UnexpectedEof` error so callers can tell a tidy shutdown apart from a
+/// truncated one.
+pub struct BcDecoder<R> {
+    rdr: R,
+    context: BcContext,
+    /// The bytes read so far but not yet consumed by a completed parse.
+    buf: Vec<u8>,
+}
+
+impl<R> BcDecoder<R> {
+    pub fn new(rdr: R) -> BcDecoder<R> {
+        BcDecoder::with_context(rdr, BcContext::new())
+    }
+
+    /// Build a decoder whose session starts from `context`. Use this to seed the
+    /// decoder with the state a later frame depends on — most importantly the
+    /// account [`Credentials`], from which the negotiated AES key is derived.
+    pub fn with_context(rdr: R, context: BcContext) -> BcDecoder<R> {
+        BcDecoder {
+            rdr,
+            context,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Build a decoder carrying the account `credentials`, from which the AES
+    /// session key is derived when a login handshake negotiates one. Without
+    /// them the key would be derived from an empty password and every encrypted
+    /// frame would decode to garbage.
+    pub fn with_credentials(rdr: R, credentials: Credentials) -> BcDecoder<R> {
+        let mut context = BcContext::new();
+        context.credentials = credentials;
+        BcDecoder::with_context(rdr, context)
+    }
+
+    /// Parse a single message out of the bytes currently buffered, returning how
+    /// many bytes it consumed on success, or how many more the parser wants on
+    /// `Incomplete`. Whether a later zero-length read is a clean end of stream is
+    /// decided by [`end_of_stream`].
+    fn parse_buffered(&mut self) -> Result<Result<(Bc, usize), usize>, Error> {
+        match bc_msg(&mut self.context, &self.buf) {
+            Ok((remaining, bc)) => {
+                let consumed = self.buf.len() - remaining.len();
+                Ok(Ok((bc, consumed)))
+            }
+            Err(nom::Err::Incomplete(needed)) => {
+                let want = match needed {
+                    nom::Needed::Size(len) => len,
+                    nom::Needed::Unknown => CHUNK_SIZE,
+                };
+                Ok(Err(want))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Drop the `consumed` leading bytes now that a message has been taken from
+    /// the front of the buffer, keeping whatever trailed it for the next parse.
+    fn advance(&mut self, consumed: usize) {
+        self.buf.drain(..consumed);
+    }
+}
+
+impl<R: Read> BcDecoder<R> {
+    /// Read the next message, blocking on the underlying reader as needed.
+    ///
+    /// Returns `Ok(None)` on a clean end of stream and an `IoError` wrapping
+    /// `UnexpectedEof` if the stream ends part-way through a frame.
+    fn next_blocking(&mut self) -> Result<Option<Bc>, Error> {
+        loop {
+            let want = match self.parse_buffered()? {
+                Ok((bc, consumed)) => {
+                    self.advance(consumed);
+                    return Ok(Some(bc));
+                }
+                Err(want) => want,
+            };
+
+            let start = self.buf.len();
+            self.buf.resize(start + want, 0);
+            let read = self.rdr.read(&mut self.buf[start..])?;
+            self.buf.truncate(start + read);
+            if read == 0 {
+                return end_of_stream(start);
+            }
+        }
+    }
+
+    /// A blocking iterator over the messages on this stream, turning the camera
+    /// socket loop into `for msg in decoder.iter_messages() { ... }`.
+    pub fn iter_messages(self) -> IterMessages<R> {
+        IterMessages { decoder: self }
+    }
+}
+
+impl<R: AsyncRead + Unpin> BcDecoder<R> {
+    /// Read the next message, awaiting the underlying reader as needed. Has the
+    /// same clean-EOF vs. mid-frame-EOF semantics as [`next_blocking`].
+    async fn next_async(&mut self) -> Result<Option<Bc>, Error> {
+        loop {
+            let want = match self.parse_buffered()? {
+                Ok((bc, consumed)) => {
+                    self.advance(consumed);
+                    return Ok(Some(bc));
+                }
+                Err(want) => want,
+            };
+
+            let start = self.buf.len();
+            self.buf.resize(start + want, 0);
+            let read = self.rdr.read(&mut self.buf[start..]).await?;
+            self.buf.truncate(start + read);
+            if read == 0 {
+                return end_of_stream(start);
+            }
+        }
+    }
+
+    /// An async `Stream` over the messages on this reader.
+    pub fn stream(self) -> impl futures::Stream<Item = Result<Bc, Error>> {
+        async_stream::try_stream! {
+            let mut decoder = self;
+            while let Some(msg) = decoder.next_async().await? {
+                yield msg;
+            }
+        }
+    }
+}
+
+/// Turn a zero-length read into either a clean end of stream (`buffer_len == 0`,
+/// we were on a message boundary) or a truncated-frame error.
+fn end_of_stream(buffer_len: usize) -> Result<Option<Bc>, Error> {
+    if buffer_len == 0 {
+        Ok(None)
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "EOF in the middle of a Baichuan frame",
+        )
+        .into())
+    }
+}
+
+/// Blocking iterator returned by [`BcDecoder::iter_messages`]. Yields `None`
+/// once the stream closes cleanly on a message boundary.
+pub struct IterMessages<R> {
+    decoder: BcDecoder<R>,
+}
+
+impl<R: Read> Iterator for IterMessages<R> {
+    type Item = Result<Bc, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.decoder.next_blocking().transpose()
+    }
+}
+
+fn bc_msg<'b>(context: &mut BcContext, buf: &'b [u8]) -> IResult<&'b [u8], Bc> {
+    let (rest, header) = bc_header(buf)?;
+    // Length of the header we just consumed, so `bc_body` can reconstruct the
+    // exact original frame (header + body) if it needs a raw fallback.
+    let header_len = buf.len() - rest.len();
+    let (rest, body) = bc_body(context, &header, buf, header_len, rest)?;
 
     let bc = Bc {
         meta: header.to_meta(),
         body,
     };
 
-    Ok((buf, bc))
+    Ok((rest, bc))
 }
 
-fn bc_body<'a, 'b, 'c>(
-    context: &'c mut BcContext,
-    header: &'a BcHeader,
+fn bc_body<'b>(
+    context: &mut BcContext,
+    header: &BcHeader,
+    frame: &'b [u8],
+    header_len: usize,
     buf: &'b [u8],
 ) -> IResult<&'b [u8], BcBody> {
     if header.is_modern() {
-        let (buf, body) = bc_modern_msg(context, header, buf)?;
-        Ok((buf, BcBody::ModernMsg(body)))
+        let (buf, parsed) = bc_modern_msg(context, header, buf)?;
+        match parsed {
+            Understood::Yes(body) => Ok((buf, BcBody::ModernMsg(body))),
+            Understood::Raw => raw_body(context, header, frame, header_len),
+        }
     } else {
-        let (buf, body) = match header.msg_id {
-            MSG_ID_LOGIN => bc_legacy_login_msg(buf)?,
-            _ => (buf, LegacyMsg::UnknownMsg),
-        };
-        Ok((buf, BcBody::LegacyMsg(body)))
+        match header.msg_id() {
+            MsgId::Login => {
+                // The login body is `body_len` bytes even though only the first
+                // 64 carry the username/password; consume the whole body so the
+                // next frame starts on the right boundary.
+                let (buf, body_buf) = take(header.body_len)(buf)?;
+                let (_, body) = bc_legacy_login_msg(body_buf)?;
+                Ok((buf, BcBody::LegacyMsg(body)))
+            }
+            _ => raw_body(context, header, frame, header_len),
+        }
+    }
+}
+
+/// Preserve a frame we could not fully understand. In `Tolerant` mode the exact
+/// original (still-encrypted) bytes are kept as [`BcBody::Raw`]; in `Strict`
+/// mode the undecodable frame is a hard error.
+fn raw_body<'b>(
+    context: &BcContext,
+    header: &BcHeader,
+    frame: &'b [u8],
+    header_len: usize,
+) -> IResult<&'b [u8], BcBody> {
+    use nom::{
+        error::{make_error, ErrorKind},
+        Err,
+    };
+
+    // Make sure the whole body is buffered before we preserve or reject the
+    // frame, and advance past exactly `header_len + body_len` bytes so the next
+    // frame is parsed from its true start rather than mid-body.
+    let (rest, _) = take(header.body_len)(&frame[header_len..])?;
+
+    match context.parse_mode {
+        ParseMode::Strict => Err(Err::Failure(make_error(rest, ErrorKind::MapRes))),
+        ParseMode::Tolerant => {
+            let frame_len = header_len + header.body_len as usize;
+            Ok((rest, BcBody::Raw(frame[..frame_len].to_vec())))
+        }
     }
 }
 
+// The explicit lifetime ties the returned closure's input and output together,
+// which closure lifetime elision cannot express here.
+#[allow(clippy::needless_lifetimes)]
 fn hex32<'a>() -> impl Fn(&'a [u8]) -> IResult<&'a [u8], String> {
     map_res(take(32usize), |slice: &'a [u8]| {
         String::from_utf8(slice.to_vec())
     })
 }
 
-fn bc_legacy_login_msg<'a>(buf: &'a [u8]) -> IResult<&'a [u8], LegacyMsg> {
+fn bc_legacy_login_msg(buf: &[u8]) -> IResult<&[u8], LegacyMsg> {
     let (buf, username) = hex32()(buf)?;
     let (buf, password) = hex32()(buf)?;
 
     Ok((buf, LegacyMsg::LoginMsg { username, password }))
 }
 
-fn bc_modern_msg<'a, 'b>(
+fn bc_modern_msg<'b>(
     context: &mut BcContext,
-    header: &'a BcHeader,
+    header: &BcHeader,
     buf: &'b [u8],
-) -> IResult<&'b [u8], ModernMsg> {
-    use nom::{
-        error::{make_error, ErrorKind},
-        Err,
-    };
-
+) -> IResult<&'b [u8], Understood<ModernMsg>> {
     let xml_len = match header.payload_offset {
         Some(off) => off,
         _ => header.body_len,
@@ -125,50 +544,74 @@ fn bc_modern_msg<'a, 'b>(
     let payload_len = header.body_len - xml_len;
     let (buf, payload_buf) = take(payload_len)(buf)?;
 
-    let decrypted;
-    let processed_xml_buf = if !header.is_encrypted() {
-        xml_buf
-    } else {
-        decrypted = xml_crypto::crypt(header.enc_offset, xml_buf);
-        &decrypted
-    };
+    // Pick the cipher from the session context rather than hardcoding the legacy
+    // XOR. When the header is not flagged encrypted the bytes are in the clear.
+    // During a mid-session re-key, both the current and previous protocols are
+    // offered so frames still in flight under the old key decode cleanly.
+    let candidates = decrypt_candidates(context, header.is_encrypted());
 
-    // Now we'll take the buffer that Nom gave a ref to and parse it.
+    // Now we'll take the buffer that Nom gave a ref to and parse it, trying each
+    // candidate cipher until the XML parses. If none produce parseable XML we
+    // don't fail here: we hand an `Understood::Raw` back up so `bc_body` can
+    // apply the active `ParseMode` (error in Strict, preserve the original frame
+    // in Tolerant).
     let xml;
+    let protocol;
     if xml_len > 0 {
-        // Apply the XML parse function, but throw away the reference to decrypted in the Ok and
-        // Err case. This error-error-error thing is the same idiom Nom uses internally.
-        let parsed = BcXmls::try_parse(processed_xml_buf)
-            .map_err(|_| Err::Error(make_error(buf, ErrorKind::MapRes)))?;
-        xml = Some(parsed);
+        let mut decoded = None;
+        for candidate in &candidates {
+            let decrypted = candidate.decrypt(header.enc_offset, xml_buf);
+            if let Ok(parsed) = BcXmls::try_parse(&decrypted) {
+                decoded = Some((parsed, candidate.clone()));
+                break;
+            }
+        }
+        match decoded {
+            Some((parsed, used)) => {
+                xml = Some(parsed);
+                protocol = used;
+            }
+            None => return Ok((buf, Understood::Raw)),
+        }
     } else {
         xml = None;
+        protocol = candidates[0].clone();
+    }
+
+    // A login negotiation carries an `Encryption` element whose nonce, combined
+    // with the account password, derives the AES session key. Install it as soon
+    // as we have decoded the element so every subsequent frame decrypts without
+    // the caller lifting a finger. `rekey` retains the protocol it replaces, so a
+    // nonce that arrives mid-session swaps the key while frames still in flight
+    // under the old one continue to decode.
+    if let Some(enc) = xml.as_ref().and_then(|doc| doc.encryption.as_ref()) {
+        if !enc.nonce.is_empty() {
+            let negotiated =
+                EncryptionProtocol::aes_from_nonce(&enc.nonce, &context.credentials.password);
+            context.rekey(negotiated);
+        }
     }
 
     // Now to handle the payload block
     // This block can either be xml or binary depending on what the message expects.
     // For our purposes we use try_parse and if all xml based parsers fail we treat
-    // As binary
+    // As binary. Decrypt it with whichever protocol decoded the XML above.
     let payload;
     if payload_len > 0 {
         // Extract remainder of message as binary, if it exists
-        let decrypted;
-        let processed_payload_buf = if !header.is_encrypted() {
-            xml_buf
-        } else {
-            decrypted = xml_crypto::crypt(header.enc_offset, payload_buf);
-            &decrypted
-        };
-        if let Ok(xml) = BcXml::try_parse(processed_payload_buf) {
+        let processed_payload_buf = protocol.decrypt(header.enc_offset, payload_buf);
+        if let Ok(xml) = BcXml::try_parse(&processed_payload_buf) {
             payload = Some(BcPayloads::BcXml(xml));
         } else {
-            payload = Some(BcPayloads::Binary(payload_buf.to_vec()));
+            // Not XML, so keep it as binary — but hand back the *decrypted* block
+            // (AES/XOR runs over the payload too), not the ciphertext we received.
+            payload = Some(BcPayloads::Binary(processed_payload_buf));
         }
     } else {
         payload = None;
     }
 
-    Ok((buf, ModernMsg { xml, payload }))
+    Ok((buf, Understood::Yes(ModernMsg { xml, payload })))
 }
 
 fn bc_header(buf: &[u8]) -> IResult<&[u8], BcHeader> {
@@ -205,18 +648,19 @@ fn test_bc_modern_login() {
     let mut context = BcContext::new();
 
     let (buf, header) = bc_header(&sample[..]).unwrap();
-    let (_, body) = bc_body(&mut context, &header, buf).unwrap();
+    let header_len = sample.len() - buf.len();
+    let (_, body) = bc_body(&mut context, &header, &sample[..], header_len, buf).unwrap();
     assert_eq!(header.msg_id, 1);
     assert_eq!(header.body_len, 145);
     assert_eq!(header.enc_offset, 0x1000000);
-    assert_eq!(header.encrypted, true);
+    assert!(header.encrypted);
     assert_eq!(header.class, 0x6614);
     match body {
         BcBody::ModernMsg(ModernMsg {
             xml: Some(ref xml),
-            binary: None,
+            payload: None,
         }) => assert_eq!(xml.encryption.as_ref().unwrap().nonce, "9E6D1FCB9E69846D"),
-        _ => assert!(false),
+        _ => panic!("unexpected message body"),
     }
 }
 
@@ -227,18 +671,19 @@ fn test_bc_legacy_login() {
     let mut context = BcContext::new();
 
     let (buf, header) = bc_header(&sample[..]).unwrap();
-    let (_, body) = bc_body(&mut context, &header, buf).unwrap();
+    let header_len = sample.len() - buf.len();
+    let (_, body) = bc_body(&mut context, &header, &sample[..], header_len, buf).unwrap();
     assert_eq!(header.msg_id, 1);
     assert_eq!(header.body_len, 1836);
     assert_eq!(header.enc_offset, 0x1000000);
-    assert_eq!(header.encrypted, true);
+    assert!(header.encrypted);
     assert_eq!(header.class, 0x6514);
     match body {
         BcBody::LegacyMsg(LegacyMsg::LoginMsg { username, password }) => {
             assert_eq!(username, "21232F297A57A5A743894A0E4A801FC\0");
             assert_eq!(password, EMPTY_LEGACY_PASSWORD);
         }
-        _ => assert!(false),
+        _ => panic!("unexpected message body"),
     }
 }
 
@@ -249,20 +694,19 @@ fn test_bc_modern_login_failed() {
     let mut context = BcContext::new();
 
     let (buf, header) = bc_header(&sample[..]).unwrap();
-    let (_, body) = bc_body(&mut context, &header, buf).unwrap();
+    let header_len = sample.len() - buf.len();
+    let (_, body) = bc_body(&mut context, &header, &sample[..], header_len, buf).unwrap();
     assert_eq!(header.msg_id, 1);
     assert_eq!(header.body_len, 0);
     assert_eq!(header.enc_offset, 0x0);
-    assert_eq!(header.encrypted, true);
+    assert!(header.encrypted);
     assert_eq!(header.class, 0x0000);
     match body {
         BcBody::ModernMsg(ModernMsg {
             xml: None,
-            binary: None,
-        }) => {
-            assert!(true);
-        }
-        _ => assert!(false),
+            payload: None,
+        }) => {}
+        _ => panic!("unexpected message body"),
     }
 }
 
@@ -273,11 +717,12 @@ fn test_bc_modern_login_success() {
     let mut context = BcContext::new();
 
     let (buf, header) = bc_header(&sample[..]).unwrap();
-    let (_, body) = bc_body(&mut context, &header, buf).unwrap();
+    let header_len = sample.len() - buf.len();
+    let (_, body) = bc_body(&mut context, &header, &sample[..], header_len, buf).unwrap();
     assert_eq!(header.msg_id, 1);
     assert_eq!(header.body_len, 2949);
     assert_eq!(header.enc_offset, 0x0);
-    assert_eq!(header.encrypted, true);
+    assert!(header.encrypted);
     assert_eq!(header.class, 0x0000);
 
     // Previously, we were not handling payload_offset == 0 (no bin offset) correctly.
@@ -285,9 +730,9 @@ fn test_bc_modern_login_success() {
     match body {
         BcBody::ModernMsg(ModernMsg {
             xml: Some(_),
-            binary: None,
-        }) => assert!(true),
-        _ => assert!(false),
+            payload: None,
+        }) => {}
+        _ => panic!("unexpected message body"),
     }
 }
 
@@ -303,19 +748,349 @@ fn test_bc_binary_mode() {
     match msg1.body {
         BcBody::ModernMsg(ModernMsg {
             xml: None,
-            binary: Some(bin),
+            payload: Some(BcPayloads::Binary(bin)),
         }) => {
             assert_eq!(bin.len(), 32);
         }
-        _ => assert!(false),
+        _ => panic!("unexpected message body"),
     }
     match msg2.body {
         BcBody::ModernMsg(ModernMsg {
             xml: None,
-            binary: Some(bin),
+            payload: Some(BcPayloads::Binary(bin)),
         }) => {
             assert_eq!(bin.len(), 30344);
         }
-        _ => assert!(false),
+        _ => panic!("unexpected message body"),
+    }
+}
+
+/// Assemble an on-the-wire frame for tests: a header (optionally carrying a
+/// `payload_offset` for the modern classes that use one) followed by `body`.
+#[cfg(test)]
+fn build_frame(
+    msg_id: u32,
+    class: u16,
+    encrypted: bool,
+    enc_offset: u32,
+    payload_offset: Option<u32>,
+    body: &[u8],
+) -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&MAGIC_HEADER.to_le_bytes());
+    frame.extend_from_slice(&msg_id.to_le_bytes());
+    frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&enc_offset.to_le_bytes());
+    frame.push(if encrypted { 1 } else { 0 }); // response_code doubles as the encrypted flag
+    frame.push(0); // ignored byte
+    frame.extend_from_slice(&class.to_le_bytes());
+    if let Some(offset) = payload_offset {
+        frame.extend_from_slice(&offset.to_le_bytes());
+    }
+    frame.extend_from_slice(body);
+    frame
+}
+
+#[test]
+fn test_aes_from_nonce_is_deterministic() {
+    let protocol = EncryptionProtocol::aes_from_nonce("nonce", "password");
+    match &protocol {
+        EncryptionProtocol::Aes { key } => {
+            // The key is the first 16 bytes of a hex digest, so all ASCII hex.
+            assert!(key.iter().all(u8::is_ascii_hexdigit));
+            // Same inputs derive the same key...
+            assert_eq!(protocol, EncryptionProtocol::aes_from_nonce("nonce", "password"));
+            // ...but the password genuinely participates.
+            assert_ne!(protocol, EncryptionProtocol::aes_from_nonce("nonce", "other"));
+        }
+        _ => panic!("expected an AES protocol"),
+    }
+}
+
+#[test]
+fn test_aes_decrypt_round_trips() {
+    let key = *b"0123456789abcdef";
+    let protocol = EncryptionProtocol::Aes { key };
+
+    let plain = b"the quick brown fox";
+    let mut ciphertext = plain.to_vec();
+    AesCfb::new_from_slices(&key, AES_IV).unwrap().encrypt(&mut ciphertext);
+    assert_ne!(ciphertext, plain);
+
+    // `enc_offset` is ignored by AES, so any value decrypts the same.
+    assert_eq!(protocol.decrypt(0x1000000, &ciphertext), plain);
+}
+
+#[test]
+fn test_rekey_keeps_prior_key_as_fallback() {
+    let first = EncryptionProtocol::Aes { key: [1u8; 16] };
+    let second = EncryptionProtocol::Aes { key: [2u8; 16] };
+
+    let mut context = BcContext::new();
+    context.rekey(first.clone());
+    context.rekey(second.clone());
+
+    assert_eq!(context.encryption_protocol, second);
+    assert_eq!(context.prior_encryption_protocol, Some(first.clone()));
+
+    // Encrypted frames are offered the current key first, then the previous one
+    // so reordered in-flight frames still decode across a re-key.
+    assert_eq!(decrypt_candidates(&context, true), vec![second, first]);
+    // Unencrypted frames ignore the session ciphers entirely.
+    assert_eq!(
+        decrypt_candidates(&context, false),
+        vec![EncryptionProtocol::Unencrypted]
+    );
+}
+
+#[test]
+fn test_aes_frame_decodes_and_installs_login_key() {
+    let nonce = "9E6D1FCB9E69846D";
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" ?>\n\
+         <body>\n<Encryption version=\"1.1\">\n<type>md5</type>\n\
+         <nonce>{}</nonce>\n</Encryption>\n</body>\n",
+        nonce
+    );
+    let key = *b"0123456789abcdef";
+    let mut ciphertext = xml.into_bytes();
+    AesCfb::new_from_slices(&key, AES_IV).unwrap().encrypt(&mut ciphertext);
+
+    // A modern login frame whose whole body is the encrypted XML (no payload).
+    let frame = build_frame(
+        MSG_ID_LOGIN,
+        0x6614,
+        true,
+        0,
+        Some(ciphertext.len() as u32),
+        &ciphertext,
+    );
+
+    let mut context = BcContext::new();
+    context.encryption_protocol = EncryptionProtocol::Aes { key };
+    context.credentials = Credentials {
+        username: "admin".to_string(),
+        password: "password".to_string(),
+    };
+
+    let bc = Bc::deserialize(&mut context, &frame[..]).unwrap();
+    match bc.body {
+        BcBody::ModernMsg(ModernMsg {
+            xml: Some(doc),
+            payload: None,
+        }) => assert_eq!(doc.encryption.unwrap().nonce, nonce),
+        _ => panic!("AES frame did not decode to XML"),
+    }
+
+    // The login nonce plus the threaded password install the session key, with
+    // the protocol it replaced retained for the re-key fallback.
+    assert_eq!(
+        context.encryption_protocol,
+        EncryptionProtocol::aes_from_nonce(nonce, "password")
+    );
+    assert!(context.prior_encryption_protocol.is_some());
+}
+
+#[test]
+fn test_with_credentials_threads_into_context() {
+    let credentials = Credentials {
+        username: "admin".to_string(),
+        password: "secret".to_string(),
+    };
+    let decoder = BcDecoder::with_credentials(&[][..], credentials.clone());
+    assert_eq!(decoder.context.credentials, credentials);
+}
+
+/// A legacy login frame whose body is a 32-byte username + 32-byte password,
+/// built to match what [`bc_legacy_login_msg`] parses out, followed by
+/// `padding` filler bytes.
+#[cfg(test)]
+fn legacy_login_frame(padding: u32) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&[b'a'; 32]);
+    body.extend_from_slice(&[b'b'; 32]);
+    // Extra trailing body the login parser must skip to stay frame-aligned.
+    body.extend(std::iter::repeat(b'z').take(padding as usize));
+    build_frame(MSG_ID_LOGIN, 0x6514, false, 0, None, &body)
+}
+
+#[test]
+fn test_decoder_retains_bytes_across_messages() {
+    // Two back-to-back frames in one buffer; the second can only parse if the
+    // bytes trailing the first are retained rather than discarded.
+    let frame = legacy_login_frame(100);
+    let mut data = frame.clone();
+    data.extend_from_slice(&frame);
+
+    let messages: Vec<Bc> = BcDecoder::new(&data[..])
+        .iter_messages()
+        .collect::<Result<_, _>>()
+        .unwrap();
+
+    assert_eq!(messages.len(), 2);
+    for message in &messages {
+        match &message.body {
+            BcBody::LegacyMsg(LegacyMsg::LoginMsg { username, password }) => {
+                assert_eq!(username, "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+                assert_eq!(password, "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb");
+            }
+            _ => panic!("unexpected message body"),
+        }
     }
 }
+
+#[test]
+fn test_decoder_clean_eof_on_message_boundary() {
+    let frame = legacy_login_frame(0);
+    let mut iter = BcDecoder::new(&frame[..]).iter_messages();
+    assert!(iter.next().is_some());
+    // The stream closed exactly on a boundary, so iteration ends with `None`.
+    assert!(iter.next().is_none());
+}
+
+#[test]
+fn test_decoder_mid_frame_eof_is_unexpected() {
+    let frame = legacy_login_frame(0);
+    let truncated = &frame[..frame.len() - 5];
+    let result = BcDecoder::new(truncated).next_blocking();
+    match result {
+        Err(Error::IoError(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+        other => panic!("expected UnexpectedEof, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_stream_yields_messages() {
+    use futures::StreamExt;
+
+    let frame = legacy_login_frame(10);
+    let mut data = frame.clone();
+    data.extend_from_slice(&frame);
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let messages: Vec<_> = BcDecoder::new(&data[..]).stream().collect().await;
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(Result::is_ok));
+    });
+}
+
+#[test]
+fn test_tolerant_preserves_raw_frame_verbatim() {
+    // A legacy frame with an unmodelled msg_id: neither login nor modern, so it
+    // falls through to the raw fallback.
+    let body = [0x01u8, 0x02, 0x03, 0x04, 0x05];
+    let frame = build_frame(99, 0x6514, false, 0, None, &body);
+
+    let mut context = BcContext::new(); // Tolerant by default
+    let bc = Bc::deserialize(&mut context, &frame[..]).unwrap();
+    match bc.body {
+        // The preserved bytes are exactly the original header + body.
+        BcBody::Raw(bytes) => assert_eq!(bytes, frame),
+        _ => panic!("expected a preserved raw frame"),
+    }
+}
+
+#[test]
+fn test_strict_rejects_unmodelled_frame() {
+    let body = [0x01u8, 0x02, 0x03, 0x04, 0x05];
+    let frame = build_frame(99, 0x6514, false, 0, None, &body);
+
+    let mut context = BcContext::new();
+    context.parse_mode = ParseMode::Strict;
+    assert!(Bc::deserialize(&mut context, &frame[..]).is_err());
+}
+
+#[test]
+fn test_msg_id_try_from() {
+    assert_eq!(MsgId::try_from(MSG_ID_LOGIN).unwrap(), MsgId::Login);
+    assert_eq!(MsgId::try_from(MSG_ID_VIDEO).unwrap(), MsgId::VideoStart);
+    assert_eq!(MsgId::try_from(MSG_ID_PTZ).unwrap(), MsgId::Ptz);
+    // Anything we do not model maps to `Unknown`, carrying the raw value.
+    assert_eq!(MsgId::try_from(12345).unwrap(), MsgId::Unknown(12345));
+}
+
+#[test]
+fn test_take_payload_extracts_and_leaves_rest_intact() {
+    use super::xml::{Encryption, Extension};
+
+    let encryption = Encryption {
+        version: "1.1".to_string(),
+        r#type: "md5".to_string(),
+        nonce: "ABCDEF".to_string(),
+    };
+    let extension = Extension {
+        binary_data: Some(1),
+    };
+    let mut bc = Bc {
+        meta: BcMeta {
+            msg_id: MSG_ID_LOGIN,
+            class: 0x6614,
+        },
+        body: BcBody::ModernMsg(ModernMsg {
+            xml: Some(BcXmls {
+                encryption: Some(encryption.clone()),
+                extension: Some(extension.clone()),
+            }),
+            payload: None,
+        }),
+    };
+
+    // Pulls the element out...
+    assert_eq!(bc.take_payload::<Encryption>().unwrap(), Some(encryption));
+    match &bc.body {
+        BcBody::ModernMsg(msg) => {
+            // ...removing it while leaving the rest of the document intact...
+            assert!(msg.xml().unwrap().encryption.is_none());
+            assert_eq!(msg.xml().unwrap().extension, Some(extension));
+        }
+        _ => panic!("unexpected message body"),
+    }
+    // ...and a second take now reports it absent.
+    assert_eq!(bc.take_payload::<Encryption>().unwrap(), None);
+}
+
+#[test]
+fn test_take_payload_none_on_legacy_and_raw() {
+    use super::xml::Encryption;
+
+    let mut legacy = Bc {
+        meta: BcMeta {
+            msg_id: MSG_ID_LOGIN,
+            class: 0x6514,
+        },
+        body: BcBody::LegacyMsg(LegacyMsg::LoginMsg {
+            username: "user".to_string(),
+            password: "pass".to_string(),
+        }),
+    };
+    assert_eq!(legacy.take_payload::<Encryption>().unwrap(), None);
+
+    let mut raw = Bc {
+        meta: BcMeta {
+            msg_id: 99,
+            class: 0x6514,
+        },
+        body: BcBody::Raw(vec![1, 2, 3]),
+    };
+    assert_eq!(raw.take_payload::<Encryption>().unwrap(), None);
+}
+
+#[test]
+fn test_modern_msg_accessors() {
+    let binary = ModernMsg {
+        xml: None,
+        payload: Some(BcPayloads::Binary(vec![1, 2, 3])),
+    };
+    assert_eq!(binary.binary(), Some(&[1, 2, 3][..]));
+    assert!(binary.xml().is_none());
+
+    let document = ModernMsg {
+        xml: Some(BcXmls::default()),
+        payload: None,
+    };
+    assert!(document.xml().is_some());
+    assert_eq!(document.binary(), None);
+}
@@ -0,0 +1,8 @@
+//! Parsing and modelling of the Baichuan ("BC") camera protocol.
+
+pub mod de;
+pub mod model;
+pub mod xml;
+pub mod xml_crypto;
+
+pub use model::*;
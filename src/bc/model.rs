@@ -0,0 +1,152 @@
+//! The in-memory model of a Baichuan message and the state the decoder threads
+//! through a connection.
+//!
+//! A message is a [`BcHeader`] describing the frame followed by a [`BcBody`],
+//! which is either a modern (XML + optional binary payload) message, a legacy
+//! fixed-layout message, or — when the body could not be understood — the raw
+//! bytes preserved verbatim. The [`BcContext`] carries the per-connection state
+//! the parser needs: how strictly to parse, and which cipher currently protects
+//! the stream.
+
+use super::de::{EncryptionProtocol, ParseMode};
+use super::xml::{BcPayloads, BcXmls};
+
+/// Every frame begins with this little-endian magic word.
+pub const MAGIC_HEADER: u32 = 0x0abc_def0;
+
+/// Login / authentication handshake.
+pub const MSG_ID_LOGIN: u32 = 1;
+/// Start of a video stream.
+pub const MSG_ID_VIDEO: u32 = 3;
+/// Pan/tilt/zoom control.
+pub const MSG_ID_PTZ: u32 = 18;
+
+/// The legacy login message pads an empty password out to 32 bytes of NUL.
+pub const EMPTY_LEGACY_PASSWORD: &str = "\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+
+/// Frames of class 0x6414 and 0x6614 carry an extra word in the header giving
+/// the offset of the binary payload; other classes, legacy and modern alike
+/// (e.g. 0x0000), do not.
+pub fn has_payload_offset(class: u16) -> bool {
+    class == 0x6414 || class == 0x6614
+}
+
+/// A fully parsed Baichuan message.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Bc {
+    pub meta: BcMeta,
+    pub body: BcBody,
+}
+
+/// The stable, decoded view of a header that travels with a parsed [`Bc`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct BcMeta {
+    pub msg_id: u32,
+    pub class: u16,
+}
+
+/// The raw, on-the-wire header as parsed straight off the socket.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BcHeader {
+    pub msg_id: u32,
+    pub body_len: u32,
+    pub enc_offset: u32,
+    pub encrypted: bool,
+    pub class: u16,
+    pub payload_offset: Option<u32>,
+}
+
+impl BcHeader {
+    /// Modern messages carry XML (and possibly a binary payload); legacy
+    /// messages have a fixed byte layout. The class word distinguishes them.
+    pub fn is_modern(&self) -> bool {
+        !matches!(self.class, 0x6514)
+    }
+
+    /// Whether the body blocks are enciphered. The camera reuses the header's
+    /// response-code byte as an "encrypted" flag.
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// Project the wire header onto the stable [`BcMeta`] kept with a parsed
+    /// message.
+    pub fn to_meta(&self) -> BcMeta {
+        BcMeta {
+            msg_id: self.msg_id,
+            class: self.class,
+        }
+    }
+}
+
+/// The body of a message, dispatched on the header's class.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BcBody {
+    /// A modern message: parsed XML plus an optional binary payload.
+    ModernMsg(ModernMsg),
+    /// A legacy fixed-layout message.
+    LegacyMsg(LegacyMsg),
+    /// A frame whose body could not be understood, kept byte-for-byte (header
+    /// and body, still enciphered) so it can be faithfully re-forwarded.
+    Raw(Vec<u8>),
+}
+
+/// A modern message: an optional top-level XML document and an optional payload
+/// block that is itself either XML or raw binary.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ModernMsg {
+    pub xml: Option<BcXmls>,
+    pub payload: Option<BcPayloads>,
+}
+
+/// The legacy, fixed-layout messages we model.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LegacyMsg {
+    LoginMsg { username: String, password: String },
+}
+
+/// The credentials used to derive a negotiated session key during login.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Per-connection decoder state.
+#[derive(Debug, Clone)]
+pub struct BcContext {
+    /// How strictly to insist on understanding every frame.
+    pub parse_mode: ParseMode,
+    /// The cipher currently protecting the stream.
+    pub encryption_protocol: EncryptionProtocol,
+    /// The cipher in force before the most recent re-key, retained so frames
+    /// still in flight under the old key continue to decrypt during a switch.
+    pub prior_encryption_protocol: Option<EncryptionProtocol>,
+    /// The account credentials, used to derive a negotiated session key.
+    pub credentials: Credentials,
+}
+
+impl BcContext {
+    pub fn new() -> BcContext {
+        BcContext {
+            parse_mode: ParseMode::default(),
+            encryption_protocol: EncryptionProtocol::default(),
+            prior_encryption_protocol: None,
+            credentials: Credentials::default(),
+        }
+    }
+
+    /// Install a freshly negotiated cipher, moving the one it replaces into
+    /// [`prior_encryption_protocol`](BcContext::prior_encryption_protocol) so
+    /// reordered in-flight frames encrypted under the old key still decode.
+    pub fn rekey(&mut self, protocol: EncryptionProtocol) {
+        let previous = std::mem::replace(&mut self.encryption_protocol, protocol);
+        self.prior_encryption_protocol = Some(previous);
+    }
+}
+
+impl Default for BcContext {
+    fn default() -> Self {
+        BcContext::new()
+    }
+}
@@ -0,0 +1,84 @@
+//! The XML documents carried by modern Baichuan messages.
+//!
+//! [`BcXmls`] is the top-level document attached to a message; [`BcPayloads`] is
+//! the trailing payload block, which is either another XML document ([`BcXml`])
+//! or opaque binary (e.g. an encoded video frame).
+
+use yaserde_derive::{YaDeserialize, YaSerialize};
+
+use super::de::{Error, TakePayload};
+use super::model::ModernMsg;
+
+/// The top-level XML document of a modern message.
+#[derive(Debug, Default, Clone, PartialEq, Eq, YaDeserialize, YaSerialize)]
+#[yaserde(rename = "body")]
+pub struct BcXmls {
+    #[yaserde(rename = "Encryption")]
+    pub encryption: Option<Encryption>,
+    #[yaserde(rename = "Extension")]
+    pub extension: Option<Extension>,
+}
+
+impl BcXmls {
+    /// Parse a top-level document from already-decrypted bytes, returning the
+    /// deserializer's error message on failure.
+    pub fn try_parse(bytes: &[u8]) -> Result<Self, String> {
+        yaserde::de::from_reader(bytes)
+    }
+}
+
+/// A payload XML document.
+#[derive(Debug, Default, Clone, PartialEq, Eq, YaDeserialize, YaSerialize)]
+#[yaserde(rename = "body")]
+pub struct BcXml {
+    #[yaserde(rename = "Extension")]
+    pub extension: Option<Extension>,
+}
+
+impl BcXml {
+    /// Parse a payload document from already-decrypted bytes.
+    pub fn try_parse(bytes: &[u8]) -> Result<Self, String> {
+        yaserde::de::from_reader(bytes)
+    }
+}
+
+/// The payload block of a modern message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BcPayloads {
+    /// The payload parsed as XML.
+    BcXml(BcXml),
+    /// The payload kept as opaque binary.
+    Binary(Vec<u8>),
+}
+
+/// The negotiated session-encryption element sent during login.
+#[derive(Debug, Default, Clone, PartialEq, Eq, YaDeserialize, YaSerialize)]
+#[yaserde(rename = "Encryption")]
+pub struct Encryption {
+    #[yaserde(attribute)]
+    pub version: String,
+    #[yaserde(rename = "type")]
+    pub r#type: String,
+    #[yaserde(rename = "nonce")]
+    pub nonce: String,
+}
+
+impl TakePayload for Encryption {
+    /// Lift the `Encryption` element out of the message's top-level XML,
+    /// removing it and leaving the rest of the document intact. Yields
+    /// `Ok(None)` when the message carries no XML or no encryption element.
+    fn take_payload(msg: &mut ModernMsg) -> Result<Option<Self>, Error> {
+        match msg.xml.as_mut() {
+            Some(doc) => Ok(doc.encryption.take()),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Declares which optional extensions a message carries.
+#[derive(Debug, Default, Clone, PartialEq, Eq, YaDeserialize, YaSerialize)]
+#[yaserde(rename = "Extension")]
+pub struct Extension {
+    #[yaserde(rename = "binaryData")]
+    pub binary_data: Option<u32>,
+}
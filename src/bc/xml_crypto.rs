@@ -0,0 +1,20 @@
+//! The legacy Baichuan "encryption": a repeating XOR of the payload against a
+//! fixed key, rotated by the message's `enc_offset` and also XORed with the low
+//! byte of that offset. The cipher is symmetric, so
+//! the same routine both encrypts and decrypts.
+
+/// The fixed key the camera XORs its legacy payloads against.
+const XML_KEY: [u8; 8] = [0x1f, 0x2d, 0x3c, 0x4b, 0x5a, 0x69, 0x78, 0xff];
+
+/// XOR `buf` against the legacy key, starting `offset` bytes into the repeating
+/// keystream, and XOR every byte with `offset as u8` as well. Returns a freshly
+/// allocated buffer of the same length as `buf`.
+pub fn crypt(offset: u32, buf: &[u8]) -> Vec<u8> {
+    buf.iter()
+        .enumerate()
+        .map(|(i, &byte)| {
+            let key = XML_KEY[(i + offset as usize) % XML_KEY.len()];
+            byte ^ key ^ (offset as u8)
+        })
+        .collect()
+}